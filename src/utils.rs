@@ -5,11 +5,16 @@ use std::mem;
 
 use smoltcp::wire::{EthernetAddress, EthernetProtocol, EthernetFrame};
 use smoltcp::wire::{IpProtocol, IpAddress, Ipv4Repr, Ipv4Packet, Ipv4Address};
+use smoltcp::wire::{Ipv6Repr, Ipv6Packet};
 use smoltcp::{Error, Result};
 use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::wire::{UdpRepr, UdpPacket};
-use smoltcp::time::Instant;
+use smoltcp::wire::{TcpRepr, TcpPacket};
+use smoltcp::wire::{Icmpv4Repr, Icmpv4Packet, Icmpv4DstUnreachable};
+use smoltcp::wire::{ArpPacket, ArpRepr, ArpOperation};
+use smoltcp::time::{Instant, Duration};
 use smoltcp::iface::{FragmentSet, FragmentedPacket};
+use std::collections::HashMap;
 
 /// Custom implementation of a mutex struct
 /// Basically a wrapper around seL4/Camkes lock/unlock calls
@@ -75,6 +80,113 @@ impl ExternalFirewallWrapper {
     }
 }
 
+/// Bit positions of the TCP control bits carried in the `flags` byte passed
+/// to `ExternalFirewallWrapperTcp::call`, so policy can e.g. drop
+/// payload-bearing SYNs or require an established connection for data.
+pub const TCP_FLAG_FIN: u8 = 0x01;
+pub const TCP_FLAG_SYN: u8 = 0x02;
+pub const TCP_FLAG_RST: u8 = 0x04;
+pub const TCP_FLAG_ACK: u8 = 0x08;
+
+/// The TCP counterpart to `ExternalFirewallWrapper`. Identical in spirit,
+/// but the callback also receives the segment's control bits, since "is
+/// this a bare SYN" or "does this data-bearing segment also carry ACK"
+/// matters for TCP policy in a way it never does for UDP.
+pub struct ExternalFirewallWrapperTcp {
+    f: unsafe extern "C" fn(u32, u16, u32, u16, u8, u16, *const u8, u16) -> i32,
+}
+
+impl ExternalFirewallWrapperTcp {
+    pub fn new(
+        f: unsafe extern "C" fn(u32, u16, u32, u16, u8, u16, *const u8, u16) -> i32,
+    ) -> ExternalFirewallWrapperTcp {
+        ExternalFirewallWrapperTcp { f: f }
+    }
+
+    pub fn call(
+        &self,
+        src_addr: u32,
+        src_port: u16,
+        dst_addr: u32,
+        dst_port: u16,
+        flags: u8,
+        payload_len: u16,
+        payload: *const u8,
+        max_payload_len: u16,
+    ) -> i32 {
+        unsafe {
+            (self.f)(
+                src_addr,
+                src_port,
+                dst_addr,
+                dst_port,
+                flags,
+                payload_len,
+                payload,
+                max_payload_len,
+            )
+        }
+    }
+}
+
+/// Maximum number of labels allowed in a single QNAME, and the maximum
+/// number of compression-pointer hops followed while expanding one. DNS
+/// names are bounded to 255 octets on the wire, so a genuine name holds at
+/// most 127 labels and can't need anywhere near this many hops; this just
+/// keeps a malicious
+/// pointer chain from spinning or reading outside the packet.
+const DNS_MAX_LABELS: usize = 128;
+const DNS_MAX_POINTER_HOPS: usize = 16;
+
+/// The DNS-aware counterpart to `ExternalFirewallWrapper`, used only for UDP
+/// traffic on port 53. In addition to the usual 4-tuple and raw payload, the
+/// callback also receives the decoded query name (dot-separated, no
+/// trailing root label) and the QTYPE/QCLASS, so policy can allow/deny by
+/// domain name rather than only by address and port.
+pub struct ExternalFirewallWrapperDns {
+    f: unsafe extern "C" fn(u32, u16, u32, u16, u16, u16, *const u8, u16, u16, *const u8, u16) -> i32,
+}
+
+impl ExternalFirewallWrapperDns {
+    pub fn new(
+        f: unsafe extern "C" fn(u32, u16, u32, u16, u16, u16, *const u8, u16, u16, *const u8, u16) -> i32,
+    ) -> ExternalFirewallWrapperDns {
+        ExternalFirewallWrapperDns { f: f }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn call(
+        &self,
+        src_addr: u32,
+        src_port: u16,
+        dst_addr: u32,
+        dst_port: u16,
+        qtype: u16,
+        qclass: u16,
+        qname: *const u8,
+        qname_len: u16,
+        payload_len: u16,
+        payload: *const u8,
+        max_payload_len: u16,
+    ) -> i32 {
+        unsafe {
+            (self.f)(
+                src_addr,
+                src_port,
+                dst_addr,
+                dst_port,
+                qtype,
+                qclass,
+                qname,
+                qname_len,
+                payload_len,
+                payload,
+                max_payload_len,
+            )
+        }
+    }
+}
+
 /// Declare static mutexes we wish to use
 /// This will get initialised the first time a thread tries to access the internal data.
 /// lazy_statics use atomic spinlocks to ensure that the structures are only initialised once.
@@ -95,6 +207,32 @@ lazy_static! {
         Arc::new(camkesrust::Mutex::new(inner).unwrap())
     };
 
+    /// a wrapper for `packet_in_tcp`
+    pub static ref FN_PACKET_IN_TCP: Arc<camkesrust::Mutex<ExternalFirewallWrapperTcp>> = {
+        let inner = ExternalFirewallWrapperTcp::new(externs::packet_in_tcp);
+        Arc::new(camkesrust::Mutex::new(inner).unwrap())
+    };
+
+    /// a wrapper for `packet_out_tcp`
+    pub static ref FN_PACKET_OUT_TCP: Arc<camkesrust::Mutex<ExternalFirewallWrapperTcp>> = {
+        let inner = ExternalFirewallWrapperTcp::new(externs::packet_out_tcp);
+        Arc::new(camkesrust::Mutex::new(inner).unwrap())
+    };
+
+    /// a wrapper for `packet_in_dns`, used instead of `FN_PACKET_IN` for UDP
+    /// traffic on port 53
+    pub static ref FN_PACKET_IN_DNS: Arc<camkesrust::Mutex<ExternalFirewallWrapperDns>> = {
+        let inner = ExternalFirewallWrapperDns::new(externs::packet_in_dns);
+        Arc::new(camkesrust::Mutex::new(inner).unwrap())
+    };
+
+    /// a wrapper for `packet_out_dns`, used instead of `FN_PACKET_OUT` for UDP
+    /// traffic on port 53
+    pub static ref FN_PACKET_OUT_DNS: Arc<camkesrust::Mutex<ExternalFirewallWrapperDns>> = {
+        let inner = ExternalFirewallWrapperDns::new(externs::packet_out_dns);
+        Arc::new(camkesrust::Mutex::new(inner).unwrap())
+    };
+
     /// fragments on rx side
     pub static ref FRAGMENTS_RX: Arc<camkesrust::Mutex<FragmentSet<'static>>> = {
         let mut fragments = FragmentSet::new(vec![]);
@@ -128,6 +266,59 @@ lazy_static! {
     /// Our mac address won't change at runtime, so we will save the value once we know it.
     pub static ref CLIENT_MAC_ADDRESS:EthernetAddress = get_device_mac();
 
+    /// Table of TCP flows whose SYN segment was approved by the external
+    /// firewall, keyed by the 4-tuple. The value is the idle-eviction
+    /// deadline, pushed forward on every segment belonging to the flow.
+    static ref TCP_CONN_TABLE: Arc<camkesrust::Mutex<HashMap<TcpConnKey, Instant>>> =
+        Arc::new(camkesrust::Mutex::new(HashMap::new()).unwrap());
+
+    /// Learned IPv4 address -> Ethernet address mappings, turning the ARP
+    /// path from an open conduit into a filtered, stateful one (akin to
+    /// smoltcp's own `NeighborCache`).
+    static ref ARP_CACHE: Arc<camkesrust::Mutex<HashMap<Ipv4Address, ArpCacheEntry>>> =
+        Arc::new(camkesrust::Mutex::new(HashMap::new()).unwrap());
+
+}
+
+/// How long a learned ARP mapping is trusted before it must be relearned.
+const ARP_CACHE_TTL_MS: i64 = 60_000;
+/// Minimum time between accepting a fresh mapping for the same address, so a
+/// flood of ARP replies can't be used to thrash the cache.
+const ARP_MIN_RELEARN_INTERVAL_MS: i64 = 1_000;
+
+/// One entry in `ARP_CACHE`.
+#[derive(Debug, Clone, Copy)]
+struct ArpCacheEntry {
+    mac: EthernetAddress,
+    learned_at: Instant,
+    expires_at: Instant,
+}
+
+/// How long an idle (no segments seen) TCP flow is kept in `TCP_CONN_TABLE`
+/// before it is evicted and has to re-establish via a fresh SYN.
+const TCP_IDLE_TIMEOUT_MS: i64 = 60_000;
+
+/// 4-tuple identifying a TCP flow in one direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct TcpConnKey {
+    src_addr: u32,
+    src_port: u16,
+    dst_addr: u32,
+    dst_port: u16,
+}
+
+/// Convert an `Ipv4Address` into the `u32` representation used to key
+/// connection tables and talk to the external firewall callback: the
+/// address bytes in network order, read as a native-endian `u32`, the same
+/// value `process_udp` hands to its callbacks.
+fn ipv4_addr_to_u32(addr: &Ipv4Address) -> u32 {
+    let bytes = addr.as_bytes();
+    u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Drop any flow in `table` whose idle deadline has already passed.
+fn evict_stale_connections(table: &mut HashMap<TcpConnKey, Instant>, now: Instant) {
+    table.retain(|_key, deadline| *deadline > now);
 }
 
 /// A safe wrapper around `client_buf` ptr
@@ -297,7 +488,10 @@ pub fn process_ethernet(
     packet_buffer: Arc<camkesrust::Mutex<Vec<Vec<u8>>>>,
     fragment_buffer: Arc<camkesrust::Mutex<FragmentSet<'static>>>,
     external_firewall_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapper>>,
+    external_firewall_tcp_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapperTcp>>,
+    external_firewall_dns_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapperDns>>,
     check_mac: bool,
+    notify_on_drop: bool,
 ) -> Result<()> {
     let eth_frame = EthernetFrame::new_checked(frame)?;
 
@@ -328,7 +522,20 @@ pub fn process_ethernet(
     match eth_frame.ethertype() {
         EthernetProtocol::Ipv4 => {
             debug_print!("Firewall process_ethernet: processing IPv4");
-            match process_ipv4(eth_frame, fragment_buffer, external_firewall_fn) {
+            // stealth deployments want silent drops; everyone else would
+            // rather the sender stop retransmitting into a black hole
+            let original_frame = if notify_on_drop {
+                Some(eth_frame.clone())
+            } else {
+                None
+            };
+            match process_ipv4(
+                eth_frame,
+                fragment_buffer,
+                external_firewall_fn,
+                external_firewall_tcp_fn,
+                external_firewall_dns_fn,
+            ) {
                 Ok(mut packets) => {
                     // enqueue frames
                     let mut buffer = packet_buffer.lock();
@@ -337,20 +544,41 @@ pub fn process_ethernet(
                         buffer.push(eth_frame.into_inner());
                     }
                 }
-                Err(e) => return Err(e),
+                Err(e) => {
+                    // a fragment held for reassembly was not dropped
+                    if e == Error::Fragmented {
+                        return Err(e);
+                    }
+                    if let Some(original_frame) = original_frame {
+                        if let Err(_icmp_err) = emit_icmp_dst_unreachable(original_frame) {
+                            debug_print!(
+                                "Firewall process_ethernet: failed to build ICMP unreachable reply: {:?}",
+                                _icmp_err
+                            );
+                        }
+                    }
+                    return Err(e);
+                }
             }
         }
         EthernetProtocol::Ipv6 => {
-            // Ipv6 traffic is not allowed
-            debug_print!("Firewall process_ethernet: dropping IPV6 traffic");
+            debug_print!("Firewall process_ethernet: processing IPv6");
+            match process_ipv6(eth_frame, external_firewall_fn) {
+                Ok(mut packets) => {
+                    // enqueue frames
+                    let mut buffer = packet_buffer.lock();
+                    while !packets.is_empty() && buffer.len() < constants::MAX_ENQUEUED_PACKETS {
+                        let eth_frame = packets.remove(0);
+                        buffer.push(eth_frame.into_inner());
+                    }
+                }
+                Err(e) => return Err(e),
+            }
         }
         EthernetProtocol::Arp => {
-            // Arp traffic is allowed, pass-through
-            debug_print!("process_ethernet client_tx: passing through ARP traffic");
-            // enqueue unchanged frame
-            let mut buffer = packet_buffer.lock();
-            if buffer.len() < constants::MAX_ENQUEUED_PACKETS {
-                buffer.push(eth_frame.into_inner());    
+            debug_print!("Firewall process_ethernet: inspecting ARP traffic");
+            if let Err(e) = process_arp(&eth_frame, packet_buffer.clone()) {
+                debug_print!("Firewall process_ethernet: dropping ARP frame: {:?}", e);
             }
         }
         _ => {
@@ -500,6 +728,21 @@ fn fragment_large_udp_packet(
     Ok(ipv4_packet_buffer)
 }
 
+/// The real IPv4 payload length, taken from the header's Total Length field
+/// rather than from the underlying buffer. Ethernet pads frames to a 60-byte
+/// minimum, so a short IP datagram riding in a minimum-size frame has extra
+/// zero bytes after it that `packet.payload().len()` (buffer-derived) would
+/// wrongly treat as part of the datagram. Returns `Error::Truncated` if the
+/// header claims more data than the buffer actually holds.
+fn ipv4_payload_len(packet: &Ipv4Packet<&[u8]>) -> Result<usize> {
+    let header_len = packet.header_len() as usize;
+    let total_len = packet.total_len() as usize;
+    if total_len < header_len || total_len > packet.as_ref().len() {
+        return Err(Error::Truncated);
+    }
+    Ok(total_len - header_len)
+}
+
 /// If there are ETH_CRC_LEN extra bytes on the end of our ipv4 packet, this is likely the CRC from the
 /// ethernet frame and need to be removed.
 fn shave_crc_from_ipv4<'frame>(
@@ -519,6 +762,169 @@ fn shave_crc_from_ipv4<'frame>(
     }
 }
 
+/// Inspect an ARP frame before it is allowed onto the wire/client:
+/// - replies that contradict an unexpired, previously-learned mapping for
+///   the same sender address are dropped (gratuitous-ARP spoofing defense)
+/// - otherwise the mapping is learned/refreshed (rate-limited to avoid cache
+///   thrashing) and the frame is forwarded
+/// - requests for our own address are answered directly instead of being
+///   forwarded
+fn process_arp(
+    eth_frame: &EthernetFrame<Vec<u8>>,
+    packet_buffer: Arc<camkesrust::Mutex<Vec<Vec<u8>>>>,
+) -> Result<()> {
+    let arp_packet = ArpPacket::new_checked(eth_frame.payload())?;
+    let arp_repr = ArpRepr::parse(&arp_packet)?;
+
+    match arp_repr {
+        ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr,
+            source_protocol_addr,
+            target_hardware_addr: _,
+            target_protocol_addr,
+        } => {
+            let now = timestamp();
+
+            {
+                let cache = ARP_CACHE.lock();
+                if let Some(entry) = cache.get(&source_protocol_addr) {
+                    if entry.expires_at > now && entry.mac != source_hardware_addr {
+                        debug_print!(
+                            "Firewall process_arp: {} claims {} but cache already maps it to {}, dropping (possible spoofing)",
+                            source_hardware_addr,
+                            source_protocol_addr,
+                            entry.mac
+                        );
+                        return Err(Error::Dropped);
+                    }
+                }
+            }
+
+            {
+                let mut cache = ARP_CACHE.lock();
+                let should_learn = match cache.get(&source_protocol_addr) {
+                    Some(entry) => {
+                        now - entry.learned_at >= Duration::from_millis(ARP_MIN_RELEARN_INTERVAL_MS as u64)
+                    }
+                    None => true,
+                };
+                if should_learn {
+                    cache.insert(
+                        source_protocol_addr,
+                        ArpCacheEntry {
+                            mac: source_hardware_addr,
+                            learned_at: now,
+                            expires_at: now + Duration::from_millis(ARP_CACHE_TTL_MS as u64),
+                        },
+                    );
+                }
+            }
+
+            if operation == ArpOperation::Request && target_protocol_addr == constants::CLIENT_IPV4_ADDRESS {
+                debug_print!("Firewall process_arp: answering ARP request for our own address directly");
+                let reply_repr = ArpRepr::EthernetIpv4 {
+                    operation: ArpOperation::Reply,
+                    source_hardware_addr: *CLIENT_MAC_ADDRESS,
+                    source_protocol_addr: target_protocol_addr,
+                    target_hardware_addr: source_hardware_addr,
+                    target_protocol_addr: source_protocol_addr,
+                };
+                let mut reply_buf = vec![0; reply_repr.buffer_len()];
+                reply_repr.emit(&mut ArpPacket::new(&mut reply_buf[..]));
+
+                let mut reply_frame =
+                    EthernetFrame::new(vec![0; constants::ETHERNET_FRAME_PAYLOAD]);
+                reply_frame.set_dst_addr(source_hardware_addr);
+                reply_frame.set_src_addr(*CLIENT_MAC_ADDRESS);
+                reply_frame.set_ethertype(EthernetProtocol::Arp);
+                let mut reply_bytes = reply_frame.into_inner();
+                reply_bytes.append(&mut reply_buf);
+
+                let mut buffer = packet_buffer.lock();
+                if buffer.len() < constants::MAX_ENQUEUED_PACKETS {
+                    buffer.push(reply_bytes);
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    // nothing disqualified this frame and we didn't answer it ourselves:
+    // forward it unchanged, same as the old blind pass-through
+    let mut buffer = packet_buffer.lock();
+    if buffer.len() < constants::MAX_ENQUEUED_PACKETS {
+        buffer.push(eth_frame.clone().into_inner());
+    }
+    Ok(())
+}
+
+/// Build an ICMPv4 Destination Unreachable (Administratively Prohibited)
+/// reply for a frame the firewall just dropped, and enqueue it onto
+/// `PACKETS_TX` so the original sender learns the packet didn't go through
+/// instead of silently retransmitting into a black hole.
+fn emit_icmp_dst_unreachable(original_frame: EthernetFrame<Vec<u8>>) -> Result<()> {
+    let checksum_caps = ChecksumCapabilities::default();
+
+    let original_ip_packet =
+        shave_crc_from_ipv4(Ipv4Packet::new_checked(original_frame.payload())?)?;
+    let original_ip_repr = Ipv4Repr::parse(&original_ip_packet, &checksum_caps)?;
+
+    // the original IP header plus the first 8 bytes of its payload, as
+    // required by RFC 792
+    let mut original_header_and_data = vec![0; original_ip_packet.header_len() as usize + 8];
+    original_ip_repr.emit(
+        &mut Ipv4Packet::new(&mut original_header_and_data[..]),
+        &checksum_caps,
+    );
+    let data_len = 8.min(original_ip_packet.payload().len());
+    let header_len = original_ip_packet.header_len() as usize;
+    original_header_and_data[header_len..header_len + data_len]
+        .copy_from_slice(&original_ip_packet.payload()[..data_len]);
+    original_header_and_data.truncate(header_len + data_len);
+
+    let icmp_repr = Icmpv4Repr::DstUnreachable {
+        reason: Icmpv4DstUnreachable::CommAdministrativelyProhibited,
+        header: original_ip_repr,
+        // `header` is emitted by the repr itself; `data` is only what follows it
+        data: &original_header_and_data[header_len..],
+    };
+
+    let mut icmp_packet_data = vec![0; icmp_repr.buffer_len()];
+    icmp_repr.emit(
+        &mut Icmpv4Packet::new(&mut icmp_packet_data[..]),
+        &checksum_caps,
+    );
+
+    let reply_ip_repr = Ipv4Repr {
+        src_addr: original_ip_repr.dst_addr,
+        dst_addr: original_ip_repr.src_addr,
+        protocol: IpProtocol::Icmp,
+        payload_len: icmp_packet_data.len(),
+        hop_limit: 64,
+    };
+    let mut reply_ip_packet =
+        Ipv4Packet::new(vec![0; reply_ip_repr.buffer_len() + icmp_packet_data.len()]);
+    reply_ip_repr.emit(&mut reply_ip_packet, &checksum_caps);
+    reply_ip_packet.payload_mut().copy_from_slice(&icmp_packet_data);
+    reply_ip_packet.fill_checksum();
+
+    let mut reply_frame = EthernetFrame::new(vec![0; constants::ETHERNET_FRAME_PAYLOAD]);
+    reply_frame.set_dst_addr(original_frame.src_addr());
+    reply_frame.set_src_addr(*CLIENT_MAC_ADDRESS);
+    reply_frame.set_ethertype(EthernetProtocol::Ipv4);
+    let mut reply_bytes = reply_frame.into_inner();
+    reply_bytes.append(&mut reply_ip_packet.into_inner());
+
+    let mut tx = PACKETS_TX.lock();
+    if tx.len() < constants::MAX_ENQUEUED_PACKETS {
+        debug_print!("Firewall emit_icmp_dst_unreachable: queuing ICMP Destination Unreachable reply");
+        tx.push(reply_bytes);
+    }
+
+    Ok(())
+}
+
 /// Return a vector of ethernet frames resulting from processing the `eth_frame`
 /// Input is a single Ipv4 ethernet frame, output can be zero or more frames
 /// Process frame:
@@ -543,6 +949,8 @@ fn process_ipv4(
     eth_frame: EthernetFrame<Vec<u8>>,
     fragment_buffer: Arc<camkesrust::Mutex<FragmentSet<'static>>>,
     external_firewall_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapper>>,
+    external_firewall_tcp_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapperTcp>>,
+    external_firewall_dns_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapperDns>>,
 ) -> Result<Vec<EthernetFrame<Vec<u8>>>> {
     // eth packet contains the original eth data
     let mut eth_packet = eth_frame.into_inner();
@@ -599,7 +1007,8 @@ fn process_ipv4(
                 // check with external firewall
                 debug_print!("Firewall process_ipv4: UDP protocol, parsing further");
                 let ident = ipv4_packet.ident();
-                match process_udp(ipv4_repr, ipv4_packet.payload(), external_firewall_fn) {
+                let udp_payload = &ipv4_packet.payload()[..ipv4_payload_len(&ipv4_packet)?];
+                match process_udp(ipv4_repr, udp_payload, external_firewall_fn, external_firewall_dns_fn) {
                     Ok(udp_packet) => {
                         debug_print!("Firewall process_ipv4: UDP packet returned, parsing/fragmenting");
                         match fragment_large_udp_packet(
@@ -629,6 +1038,40 @@ fn process_ipv4(
                     }
                 }
             }
+            IpProtocol::Tcp => {
+                // check with external firewall, enforcing flow state
+                debug_print!("Firewall process_ipv4: TCP protocol, parsing further");
+                let ident = ipv4_packet.ident();
+                let tcp_payload = &ipv4_packet.payload()[..ipv4_payload_len(&ipv4_packet)?];
+                match process_tcp(ipv4_repr, tcp_payload, external_firewall_tcp_fn) {
+                    Ok(tcp_packet) => {
+                        debug_print!("Firewall process_ipv4: TCP segment approved, rebuilding packet");
+                        let tcp_data = tcp_packet.into_inner();
+                        let ip_repr = Ipv4Repr {
+                            src_addr: ipv4_repr.src_addr,
+                            dst_addr: ipv4_repr.dst_addr,
+                            protocol: IpProtocol::Tcp,
+                            payload_len: tcp_data.len(),
+                            hop_limit: ipv4_repr.hop_limit,
+                        };
+                        let mut ip_packet =
+                            Ipv4Packet::new(vec![0; ip_repr.buffer_len() + tcp_data.len()]);
+                        ip_repr.emit(&mut ip_packet, &ChecksumCapabilities::default());
+                        ip_packet.set_ident(ident);
+                        ip_packet.payload_mut().copy_from_slice(&tcp_data);
+                        ip_packet.fill_checksum();
+                        ipv4_packet_buffer.push(ip_packet);
+                    }
+                    Err(e) => {
+                        let e = Err(e);
+                        debug_print!(
+                            "Firewall process_ipv4: drop TCP segment, return {:?}",
+                            e
+                        );
+                        return e;
+                    }
+                }
+            }
             _ => {
                 // unknown protocol, drop packet
                 let e = Err(Error::Unrecognized);
@@ -660,106 +1103,969 @@ fn process_ipv4(
     Ok(eth_packet_buffer)
 }
 
-/// Process an IPv4 fragment
-/// Returns etiher a vector representing an assembled packet,
-/// nothing (in case no packets are available),
-/// or and error caused by fragment processing
-fn process_ipv4_fragment<'frame, 'r>(
-    ipv4_packet: Ipv4Packet<&'frame [u8]>,
-    timestamp: Instant,
-    fragments: &'r mut FragmentSet<'static>,
-) -> Result<Option<Vec<u8>>> {
-    debug_print!("Firewall process_ipv4_fragment: got a fragment with id = {}", ipv4_packet.ident());
-    // get an existing fragment or attempt to get a new one
-    let fragment = match fragments.get_packet(
-        ipv4_packet.ident(),
-        ipv4_packet.src_addr(),
-        ipv4_packet.dst_addr(),
-        timestamp,
-    ) {
-        Some(frag) => frag,
-        None => return Err(Error::FragmentSetFull),
-    };
-
-    if fragment.is_empty() {
-        // this is a new packet
-        debug_print!("Firewall process_ipv4_fragment: fragment is empty");
-        fragment.start(
-            ipv4_packet.ident(),
-            ipv4_packet.src_addr(),
-            ipv4_packet.dst_addr(),
-        );
+/// Walk the IPv6 extension header chain starting at `next_header`, skipping
+/// over Hop-by-Hop, Routing and Destination Options headers until an upper
+/// layer protocol (or the Fragment header) is reached.
+/// Returns the protocol of the upper layer payload, the offset (from the
+/// start of `payload`) at which it begins, and the *absolute* offset (from
+/// the start of the full IPv6 datagram, fixed header included) of the Next
+/// Header byte that names it — the fixed header's own Next Header field
+/// (byte 6) if the chain had no extension headers, or the last extension
+/// header's own Next Header byte (its first byte) otherwise. Callers that
+/// rewrite the chain (e.g. after fragment reassembly replaces the Fragment
+/// header with real upper-layer data) need that offset to patch the right
+/// byte.
+fn ipv6_skip_extension_headers(
+    payload: &[u8],
+    next_header: IpProtocol,
+) -> Result<(IpProtocol, usize, usize)> {
+    let mut next_header = next_header;
+    let mut offset = 0;
+    let mut next_header_field_offset = 6;
+
+    loop {
+        match next_header {
+            IpProtocol::HopByHop | IpProtocol::Ipv6Route | IpProtocol::Ipv6Opts => {
+                if payload.len() < offset + 2 {
+                    return Err(Error::Truncated);
+                }
+                let hdr_next_header = IpProtocol::from(payload[offset]);
+                let hdr_len = (payload[offset + 1] as usize + 1) * 8;
+                next_header_field_offset = 40 + offset;
+                next_header = hdr_next_header;
+                offset += hdr_len;
+            }
+            other => return Ok((other, offset, next_header_field_offset)),
+        }
     }
+}
 
-    if !ipv4_packet.more_frags() {
-        // last fragment, remember data length
-        debug_print!("Firewall process_ipv4_fragment: this is the last fragment");
-        fragment
-            .set_total_len(ipv4_packet.frag_offset() as usize + ipv4_packet.total_len() as usize);
+/// The real IPv6 payload length (everything after the fixed 40-byte header:
+/// extension headers plus upper-layer data), taken from the header's Payload
+/// Length field rather than from the underlying buffer. Same Ethernet
+/// tail-padding problem `ipv4_payload_len` guards against: a short IPv6
+/// datagram riding in a minimum-size frame has extra zero bytes after it
+/// that `packet.payload().len()` (buffer-derived) would wrongly treat as
+/// part of the datagram. Returns `Error::Truncated` if the header claims
+/// more data than the buffer actually holds.
+fn ipv6_payload_len(packet: &Ipv6Packet<&[u8]>) -> Result<usize> {
+    let payload_len = packet.payload_len() as usize;
+    if payload_len > packet.payload().len() {
+        return Err(Error::Truncated);
     }
+    Ok(payload_len)
+}
 
-    match fragment.add(
-        ipv4_packet.header_len() as usize,
-        ipv4_packet.frag_offset() as usize,
-        ipv4_packet.payload().len(),
-        ipv4_packet.into_inner(),
-        timestamp,
-    ) {
-        Ok(_) => {
-            debug_print!("Firewall process_ipv4_fragment: adding fragment OK");
-        }
-        Err(_e) => {
-            debug_print!("Firewall process_ipv4_fragment: adding fragment error {:?}", _e);
-            fragment.reset();
-            return Err(Error::TooManyFragments);
+/// Return a vector of ethernet frames resulting from processing the `eth_frame`
+/// Input is a single Ipv6 ethernet frame, output can be zero or more frames.
+/// Process frame:
+///  - walk the extension header chain to find the upper layer protocol
+///  - Fragment extension header: reassemble via `process_ipv6_fragment`
+///  - ICMPv6 (neighbor discovery etc.): pass through unchanged
+///  - UDP: check payload further via the external firewall, same as IPv4
+///  - other: drop
+fn process_ipv6(
+    eth_frame: EthernetFrame<Vec<u8>>,
+    external_firewall_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapper>>,
+) -> Result<Vec<EthernetFrame<Vec<u8>>>> {
+    let mut eth_packet = eth_frame.into_inner();
+    let mut eth_packet_buffer: Vec<EthernetFrame<Vec<u8>>> = vec![];
+
+    let mut eth_payload = {
+        let mut payload = vec![];
+        payload.extend_from_slice(&eth_packet[constants::ETHERNET_FRAME_PAYLOAD..]);
+        payload
+    };
+
+    {
+        // reassemble first, if this is a fragment. Once reassembly rewrites
+        // the chain to look like an unfragmented datagram, `eth_payload`
+        // falls through to the normal dispatch below exactly as a
+        // never-fragmented one would — it is never handed to the caller
+        // without passing through the external firewall, same as process_ipv4.
+        let ipv6_packet = Ipv6Packet::new_checked(&eth_payload[..])?;
+        let (upper_protocol, ext_offset, next_header_field_offset) =
+            ipv6_skip_extension_headers(ipv6_packet.payload(), ipv6_packet.next_header())?;
+
+        if upper_protocol == IpProtocol::Ipv6Frag {
+            debug_print!("Firewall process_ipv6: fragmented packet detected");
+            let mut fragments = IPV6_FRAGMENTS_RX.lock();
+            match process_ipv6_fragment(&ipv6_packet, ext_offset, timestamp(), &mut fragments)? {
+                Some(mut assembled) => {
+                    if assembled.len() < 40 + ext_offset + 8 {
+                        return Err(Error::Truncated);
+                    }
+                    // the Fragment header's own Next Header byte names the
+                    // real upper-layer protocol; save it before the header
+                    // is stripped below
+                    let real_upper_protocol = assembled[40 + ext_offset];
+
+                    // strip the now-stale 8-byte Fragment header so the
+                    // reassembled datagram looks exactly like one that was
+                    // never fragmented
+                    assembled.drain(40 + ext_offset..40 + ext_offset + 8);
+
+                    // point whichever header used to say "Fragment" at the
+                    // real upper-layer protocol instead
+                    assembled[next_header_field_offset] = real_upper_protocol;
+
+                    // the fixed IPv6 header is always 40 bytes; everything
+                    // after it (extension headers + upper-layer payload)
+                    // counts towards the Payload Length field, which the
+                    // fragments' original headers no longer reflect once
+                    // stitched together
+                    let new_payload_len = (assembled.len() - 40) as u16;
+                    assembled[4..6].copy_from_slice(&new_payload_len.to_be_bytes());
+                    eth_payload = assembled;
+                }
+                None => return Err(Error::Fragmented),
+            }
         }
     }
 
-    if fragment.check_contig_range() {
-        // this is the last packet, attempt reassembly
-        let front = match fragment.front() {
-            Some(f) => {
-                debug_print!("Firewall process_ipv4_fragment: fragment reassembly Some");
-                f
+    {
+        // re-derive the upper layer protocol from (possibly just-reassembled)
+        // `eth_payload`, so a datagram that arrived fragmented goes through
+        // exactly the same filtering as one that didn't
+        let ipv6_packet = Ipv6Packet::new_checked(&eth_payload[..])?;
+        let (upper_protocol, ext_offset, _next_header_field_offset) =
+            ipv6_skip_extension_headers(ipv6_packet.payload(), ipv6_packet.next_header())?;
+
+        debug_print!("Firewall process_ipv6: upper layer protocol = {}", upper_protocol);
+
+        match upper_protocol {
+            IpProtocol::Icmpv6 => {
+                // neighbor discovery and friends: pass through unchanged
+                debug_print!("Firewall process_ipv6: ICMPv6 protocol, returning unchanged");
             }
-            None => {
-                debug_print!("Firewall process_ipv4_fragment: fragment reassebly None, return Ok(None)");
-                return Ok(None);
+            IpProtocol::Udp => {
+                debug_print!("Firewall process_ipv6: UDP protocol, parsing further");
+                let ipv6_repr = Ipv6Repr::parse(&ipv6_packet)?;
+                let payload_len = ipv6_payload_len(&ipv6_packet)?;
+                if payload_len < ext_offset {
+                    return Err(Error::Truncated);
+                }
+                let udp_payload = &ipv6_packet.payload()[ext_offset..payload_len];
+                match process_udp_v6(ipv6_repr, udp_payload, external_firewall_fn) {
+                    Ok(udp_packet) => {
+                        // everything up to (and including) the extension header
+                        // chain is kept as-is; only the UDP datagram is replaced
+                        let header_len = eth_payload.len() - ipv6_packet.payload().len() + ext_offset;
+                        let mut new_payload = vec![];
+                        new_payload.extend_from_slice(&eth_payload[..header_len]);
+                        new_payload.extend_from_slice(&udp_packet.into_inner());
+                        // the external firewall may shrink the UDP payload;
+                        // the Payload Length field has to follow, same as the
+                        // fragment-reassembly branch above already does
+                        let new_payload_len = (new_payload.len() - 40) as u16;
+                        new_payload[4..6].copy_from_slice(&new_payload_len.to_be_bytes());
+                        eth_payload = new_payload;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            _ => {
+                debug_print!("Firewall process_ipv6: unrecognized upper layer protocol, dropping");
+                return Err(Error::Unrecognized);
             }
-        };
-        {
-            // because the different mutability of the underlying buffers, we have to do this exercise
-            let mut ipv4_packet = Ipv4Packet::new_checked(fragment.get_buffer_mut(0, front))?;
-            ipv4_packet.set_total_len(front as u16);
-            ipv4_packet.fill_checksum();
         }
-        let ret = {
-            let mut ret = vec![0; front];
-            ret.clone_from_slice(fragment.get_buffer(0, front));
-            ret
-        };
-        fragment.reset();
-        return Ok(Some(ret));
     }
 
-    // not the last fragment
-    let r = Ok(None);
-    debug_print!("Firewall process_ipv4_fragment: this wasn't the last fragment, returning {:?}", r);
-    return r;
+    eth_packet.truncate(constants::ETHERNET_FRAME_PAYLOAD);
+    eth_packet.append(&mut eth_payload);
+    eth_packet_buffer.push(EthernetFrame::new_checked(eth_packet)?);
+
+    Ok(eth_packet_buffer)
 }
 
-/// Process UDP data and eithe return an DP packet approved by the external firewall,
-/// or an error (including Error:Dropped)
-/// The processing is following:
-/// - parse UDP packet
-/// - create a new vector with the payload
-/// - call external firewall (if not NULL)
-/// - if approved, assembled a new UDP packet
+/// Process an IPv6 Fragment extension header.
+/// Behaves like `process_ipv4_fragment` and reuses the same
+/// `FRAGMENT_DEADLINES_RX` TTL/overlap/size bookkeeping (keyed generically
+/// over `IpAddress` so both families share it), but reassembles into
+/// `IPV6_FRAGMENTS_RX` rather than `FragmentSet` (see that pool's doc
+/// comment for why), and keys reassembly on the full 32-bit identification
+/// carried by the Fragment header rather than the 16-bit IPv4 ident field.
+/// The atomic-fragment case (M=0, offset=0) is passed straight through
+/// without entering the pool at all.
+fn process_ipv6_fragment<'frame, 'r>(
+    ipv6_packet: &Ipv6Packet<&'frame [u8]>,
+    frag_header_offset: usize,
+    timestamp: Instant,
+    fragments: &'r mut HashMap<FragmentKey, FragmentedPacket<'static>>,
+) -> Result<Option<Vec<u8>>> {
+    let frag_header = &ipv6_packet.payload()[frag_header_offset..];
+    if frag_header.len() < 8 {
+        return Err(Error::Truncated);
+    }
+
+    let frag_offset = (((frag_header[2] as u16) << 8 | frag_header[3] as u16) >> 3) as usize * 8;
+    let more_frags = frag_header[3] & 0x1 != 0;
+    let ident = u32::from_be_bytes([frag_header[4], frag_header[5], frag_header[6], frag_header[7]]);
+    let payload = &frag_header[8..];
+    // the reassembly buffer keeps the fixed 40-byte header, any extension
+    // headers and the Fragment header ahead of the payload, the way the
+    // IPv4 path keeps the IP header ahead of its payload
+    let header_len = 40 + frag_header_offset + 8;
+
+    if frag_offset == 0 && !more_frags {
+        // atomic fragment: nothing to reassemble, deliver as-is
+        debug_print!("Firewall process_ipv6_fragment: atomic fragment for id = {}, passing through", ident);
+        return Ok(Some(ipv6_packet.as_ref().to_vec()));
+    }
+
+    debug_print!("Firewall process_ipv6_fragment: got a fragment with id = {}", ident);
+
+    let src_addr = IpAddress::from(ipv6_packet.src_addr());
+    let dst_addr = IpAddress::from(ipv6_packet.dst_addr());
+    let key = FragmentKey {
+        ident,
+        src_addr,
+        dst_addr,
+    };
+
+    fragment_pool_v6_sweep(fragments, timestamp);
+
+    {
+        let mut deadlines = FRAGMENT_DEADLINES_RX.lock();
+        if !deadlines.contains_key(&key) {
+            let deadline = timestamp + Duration::from_millis(REASSEMBLY_TIMEOUT_MS as u64);
+            deadlines.insert(
+                key,
+                FragmentState {
+                    deadline,
+                    buffered_bytes: 0,
+                    filled_ranges: vec![],
+                },
+            );
+        }
+    }
+
+    // the IPv6 minimum MTU (1280) bounds how large a reassembled datagram we
+    // should ever have to deal with in practice; reuse the same hard ceiling
+    // as IPv4's 65535 so a malicious fragment chain can't grow it unbounded
+    let new_range = (frag_offset, frag_offset + payload.len());
+    if new_range.1 > 65535 {
+        debug_print!("Firewall process_ipv6_fragment: reassembled datagram would exceed 65535 bytes");
+        FRAGMENT_DEADLINES_RX.lock().remove(&key);
+        return Err(Error::Malformed);
+    }
+
+    if !fragments.contains_key(&key) && fragments.len() >= constants::SUPPORTED_FRAGMENTS {
+        return Err(Error::FragmentSetFull);
+    }
+    let fragment = fragments.entry(key).or_insert_with(|| {
+        let mut fragment = FragmentedPacket::new(vec![0; constants::MAX_REASSEMBLED_FRAGMENT_SIZE]);
+        fragment.start(ident as u16, src_addr, dst_addr);
+        fragment
+    });
+
+    {
+        let mut deadlines = FRAGMENT_DEADLINES_RX.lock();
+        if let Some(state) = deadlines.get_mut(&key) {
+            let new_bytes = new_bytes_in_range(&state.filled_ranges, new_range);
+            let overlaps_existing = new_bytes < payload.len();
+
+            if overlaps_existing {
+                match FRAGMENT_OVERLAP_POLICY {
+                    FragmentOverlapPolicy::Strict => {
+                        if overlap_conflicts(fragment, header_len, frag_offset, payload, &state.filled_ranges, new_range) {
+                            debug_print!(
+                                "Firewall process_ipv6_fragment: conflicting overlapping fragment for id = {}, Strict policy resets the reassembly",
+                                key.ident
+                            );
+                            fragment.reset();
+                            deadlines.remove(&key);
+                            fragments.remove(&key);
+                            return Err(Error::Malformed);
+                        }
+                        debug_print!(
+                            "Firewall process_ipv6_fragment: overlapping fragment for id = {} is a byte-identical retransmission, tolerating it",
+                            key.ident
+                        );
+                    }
+                    FragmentOverlapPolicy::FirstWins => {
+                        debug_print!(
+                            "Firewall process_ipv6_fragment: overlapping fragment for id = {}, FirstWins policy keeps the earlier data",
+                            key.ident
+                        );
+                        return Ok(None);
+                    }
+                    FragmentOverlapPolicy::LastWins => {}
+                }
+            }
+
+            // only genuinely new bytes count against the budget; see
+            // `new_bytes_in_range`
+            state.buffered_bytes += new_bytes;
+            if state.buffered_bytes > constants::MAX_REASSEMBLED_FRAGMENT_SIZE {
+                debug_print!(
+                    "Firewall process_ipv6_fragment: reassembly for id = {} exceeds MAX_REASSEMBLED_FRAGMENT_SIZE, dropping",
+                    key.ident
+                );
+                fragment.reset();
+                deadlines.remove(&key);
+                fragments.remove(&key);
+                return Err(Error::Malformed);
+            }
+
+            merge_range(&mut state.filled_ranges, new_range);
+        }
+    }
+
+    if !more_frags {
+        fragment.set_total_len(header_len + frag_offset + payload.len());
+    }
+
+    match fragment.add(
+        header_len,
+        frag_offset,
+        payload.len(),
+        ipv6_packet.as_ref().to_vec(),
+        timestamp,
+    ) {
+        Ok(_) => {}
+        Err(_e) => {
+            fragment.reset();
+            FRAGMENT_DEADLINES_RX.lock().remove(&key);
+            fragments.remove(&key);
+            return Err(Error::TooManyFragments);
+        }
+    }
+
+    if fragment.check_contig_range() {
+        let front = match fragment.front() {
+            Some(f) => f,
+            None => return Ok(None),
+        };
+        let ret = {
+            let mut ret = vec![0; front];
+            ret.clone_from_slice(fragment.get_buffer(0, front));
+            ret
+        };
+        fragment.reset();
+        FRAGMENT_DEADLINES_RX.lock().remove(&key);
+        fragments.remove(&key);
+        return Ok(Some(ret));
+    }
+
+    Ok(None)
+}
+
+/// Like `process_udp`, but for UDP carried over IPv6. Parses the payload,
+/// runs it past the external firewall and reassembles it (with a corrected
+/// checksum over the IPv6 pseudo-header) if approved.
+fn process_udp_v6<'frame>(
+    ip_repr: Ipv6Repr,
+    ip_payload: &'frame [u8],
+    external_firewall_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapper>>,
+) -> Result<UdpPacket<Vec<u8>>> {
+    let udp_packet = UdpPacket::new_checked(ip_payload)?;
+    let checksum_caps = ChecksumCapabilities::default();
+    let _udp_repr = UdpRepr::parse(
+        &udp_packet,
+        &IpAddress::from(ip_repr.src_addr),
+        &IpAddress::from(ip_repr.dst_addr),
+        &checksum_caps,
+    )?; // to force checksum
+
+    // the external firewall callback only understands IPv4 addresses today;
+    // until it grows v6 support we hash the address down to 32 bits so that
+    // at least distinct peers are distinguishable to policy callbacks.
+    let src_addr_bytes = ipv6_addr_to_u32(&ip_repr.src_addr);
+    let dst_addr_bytes = ipv6_addr_to_u32(&ip_repr.dst_addr);
+
+    let mut udp_data = Vec::with_capacity(constants::MAX_UDP_PAYLOAD_SIZE);
+    udp_data.extend_from_slice(udp_packet.payload());
+    let data_len = udp_data.len();
+    let max_data_len = udp_data.capacity();
+    let data_ptr = udp_data.as_mut_ptr();
+
+    debug_print!(
+        "Firewall process_udp_v6: calling external firewall.
+        src_addr = {},
+        udp_packet.src_port = {},
+        dst_addr = {},
+        udp_packet.dst_port = {},
+        udp payload len = {}
+        buffer size = {}",
+        ip_repr.src_addr,
+        udp_packet.src_port(),
+        ip_repr.dst_addr,
+        udp_packet.dst_port(),
+        data_len as u16,
+        max_data_len as u16,
+    );
+
+    let payload_len = external_firewall_fn.lock().call(
+        src_addr_bytes,
+        udp_packet.src_port(),
+        dst_addr_bytes,
+        udp_packet.dst_port(),
+        data_len as u16,
+        data_ptr,
+        max_data_len as u16,
+    );
+
+    unsafe {
+        mem::forget(udp_data);
+        udp_data = Vec::from_raw_parts(data_ptr, payload_len as usize, max_data_len);
+    }
+
+    if payload_len > 0 && payload_len as usize <= constants::MAX_UDP_PACKET_SIZE {
+        debug_print!("Firewall process_udp_v6: packet approved, reassembling with payload len = {}",
+            payload_len
+        );
+        let udp_repr = UdpRepr {
+            src_port: udp_packet.src_port(),
+            dst_port: udp_packet.dst_port(),
+            payload: &udp_data,
+        };
+        let mut udp_packet_data = vec![0; udp_repr.buffer_len()];
+        {
+            let mut udp_packet = UdpPacket::new(udp_packet_data.as_mut_slice());
+            udp_repr.emit(
+                &mut udp_packet,
+                &IpAddress::from(ip_repr.src_addr),
+                &IpAddress::from(ip_repr.dst_addr),
+                &ChecksumCapabilities::default(),
+            );
+            udp_packet.fill_checksum(
+                &IpAddress::from(ip_repr.src_addr),
+                &IpAddress::from(ip_repr.dst_addr),
+            );
+        }
+
+        Ok(UdpPacket::new_checked(udp_packet_data)?)
+    } else {
+        let e = Err(Error::Dropped);
+        debug_print!("Firewall process_udp_v6: packet dropped, returning {:?}", e);
+        e
+    }
+}
+
+/// Fold an IPv6 address down to a 32-bit value for callbacks that only
+/// understand IPv4-shaped addresses.
+fn ipv6_addr_to_u32(addr: &smoltcp::wire::Ipv6Address) -> u32 {
+    let bytes = addr.as_bytes();
+    let mut folded: u32 = 0;
+    for chunk in bytes.chunks(4) {
+        let mut buf = [0u8; 4];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        folded ^= u32::from_be_bytes(buf);
+    }
+    folded
+}
+
+/// How long a partially-reassembled IPv4 datagram is kept in `FRAGMENTS_RX`
+/// before its slot is reclaimed. RFC 791 suggests ~15s; we use the same
+/// figure here.
+const REASSEMBLY_TIMEOUT_MS: i64 = 15_000;
+
+/// Policy applied when a new fragment overlaps bytes of the same datagram
+/// already received from an earlier fragment. `smoltcp::Error` has no
+/// variants of its own for this (it's a vendored, fixed enum), so a conflict
+/// rejected under `Strict` surfaces as `Error::Malformed` to the caller;
+/// under `FirstWins` the later fragment is dropped with `Ok(None)`, and under
+/// `LastWins` it is accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FragmentOverlapPolicy {
+    /// Keep whichever fragment covering a given byte arrived first; a later,
+    /// overlapping fragment is dropped in its entirety rather than allowed
+    /// to rewrite bytes (the underlying `FragmentedPacket::add` has no way
+    /// to splice in only the non-overlapping portion of a fragment).
+    FirstWins,
+    /// An overlap is only treated as an attack (teardrop/overlap evasion)
+    /// when the overlapping bytes actually disagree with what's already
+    /// buffered; a byte-identical overlapping retransmission — normal on a
+    /// lossy link — is tolerated. A genuine conflict resets the reassembly.
+    Strict,
+    /// The newest fragment always wins; overlapping bytes are overwritten.
+    LastWins,
+}
+
+/// The policy this firewall enforces for overlapping IPv4 fragments.
+const FRAGMENT_OVERLAP_POLICY: FragmentOverlapPolicy = FragmentOverlapPolicy::Strict;
+
+/// Key identifying one in-progress reassembly (IPv4 or IPv6) in
+/// `FRAGMENT_DEADLINES_RX`. Identification field width differs between IPv4
+/// (16 bits) and the IPv6 Fragment header (32 bits); this keeps the wider of
+/// the two. IPv4 reassembly still goes through `FragmentSet`, whose
+/// `get_packet` only keys slots on the low 16 bits of the ident it's given
+/// (fine for IPv4, whose ident *is* 16 bits) — IPv6 cannot use it safely for
+/// that reason and instead gets its own pool, `IPV6_FRAGMENTS_RX`, keyed
+/// directly on the full `FragmentKey` below.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    ident: u32,
+    src_addr: IpAddress,
+    dst_addr: IpAddress,
+}
+
+/// Bookkeeping for one in-progress IPv4 or IPv6 reassembly, kept alongside the
+/// `FragmentedPacket` slot itself (which knows nothing about time or which
+/// byte ranges it has already received).
+struct FragmentState {
+    deadline: Instant,
+    buffered_bytes: usize,
+    /// Sorted, non-overlapping `[start, end)` byte ranges (relative to the
+    /// reassembled payload) already filled in.
+    filled_ranges: Vec<(usize, usize)>,
+}
+
+lazy_static! {
+    /// Deadline, byte budget and filled-range tracking for every in-progress
+    /// reassembly in `FRAGMENTS_RX` and `IPV6_FRAGMENTS_RX`. `FragmentSet` itself has no notion of
+    /// time or overlap, so without this side table a fragment slot occupied
+    /// by a flow that never completes would never be reclaimed (letting a
+    /// remote host exhaust `SUPPORTED_FRAGMENTS`), and overlapping fragments
+    /// with conflicting data would be silently reassembled.
+    static ref FRAGMENT_DEADLINES_RX: Arc<camkesrust::Mutex<HashMap<FragmentKey, FragmentState>>> =
+        Arc::new(camkesrust::Mutex::new(HashMap::new()).unwrap());
+
+    /// Reassembly pool for IPv6 fragments, separate from `FRAGMENTS_RX`.
+    /// `FragmentSet::get_packet` truncates the ident it's given to 16 bits
+    /// when picking a slot, which is harmless for IPv4 (whose ident field
+    /// really is 16 bits) but wrong for IPv6's 32-bit Fragment-header
+    /// identification: two unrelated IPv6 flows between the same hosts
+    /// whose idents merely share the same low 16 bits would collide onto
+    /// the same slot and have their fragment bytes interleave. Keying
+    /// directly on the full `FragmentKey` here avoids that entirely. Capped
+    /// at `constants::SUPPORTED_FRAGMENTS` entries, same as `FragmentSet`.
+    static ref IPV6_FRAGMENTS_RX: Arc<camkesrust::Mutex<HashMap<FragmentKey, FragmentedPacket<'static>>>> =
+        Arc::new(camkesrust::Mutex::new(HashMap::new()).unwrap());
+}
+
+/// Return the overlap of two half-open ranges, if any.
+fn range_overlap(a: (usize, usize), b: (usize, usize)) -> Option<(usize, usize)> {
+    let start = a.0.max(b.0);
+    let end = a.1.min(b.1);
+    if start < end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// How many bytes of `new_range` aren't already covered by `filled_ranges`.
+/// `filled_ranges` is kept sorted and coalesced by `merge_range`, so its
+/// entries never overlap each other and the overlaps with `new_range` can
+/// just be summed without double-counting. Used to charge a fragment's
+/// `buffered_bytes` budget only for genuinely new data — a retransmission
+/// that's tolerated as a duplicate (or replayed under `LastWins`) shouldn't
+/// count again toward `MAX_REASSEMBLED_FRAGMENT_SIZE`, or a handful of
+/// harmless retransmits on a lossy link could trip that cap on their own.
+fn new_bytes_in_range(filled_ranges: &[(usize, usize)], new_range: (usize, usize)) -> usize {
+    let covered: usize = filled_ranges
+        .iter()
+        .filter_map(|&existing_range| range_overlap(new_range, existing_range))
+        .map(|(start, end)| end - start)
+        .sum();
+    (new_range.1 - new_range.0) - covered
+}
+
+/// Whether `new_range`'s overlap with any already-filled range actually
+/// disagrees with the bytes already buffered there. A duplicate
+/// retransmission of a fragment we've already seen — a normal, non-malicious
+/// occurrence on a lossy link — overlaps by definition but carries identical
+/// bytes; only a genuine teardrop/overlap-evasion attempt rewrites the
+/// region with *different* data. `frag_start`/`payload` describe the new
+/// fragment (`payload[i]` is the byte at absolute offset `frag_start + i`).
+/// `header_len` is the length of the header `fragment` keeps ahead of the
+/// reassembled payload in its buffer.
+fn overlap_conflicts(
+    fragment: &mut FragmentedPacket<'static>,
+    header_len: usize,
+    frag_start: usize,
+    payload: &[u8],
+    filled_ranges: &[(usize, usize)],
+    new_range: (usize, usize),
+) -> bool {
+    filled_ranges.iter().any(|&existing_range| {
+        match range_overlap(new_range, existing_range) {
+            Some((start, end)) => {
+                let existing_bytes = fragment.get_buffer(header_len + start, header_len + end);
+                let new_bytes = &payload[start - frag_start..end - frag_start];
+                existing_bytes != new_bytes
+            }
+            None => false,
+        }
+    })
+}
+
+/// Merge `new_range` into `ranges`, keeping the list sorted and coalesced.
+/// Assumes `new_range` does not conflict with any existing entry (that must
+/// be checked separately via `range_overlap` before data is written).
+fn merge_range(ranges: &mut Vec<(usize, usize)>, new_range: (usize, usize)) {
+    ranges.push(new_range);
+    ranges.sort_unstable_by_key(|r| r.0);
+    let mut merged: Vec<(usize, usize)> = vec![];
+    for range in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if range.0 <= last.1 => last.1 = last.1.max(range.1),
+            _ => merged.push(range),
+        }
+    }
+    *ranges = merged;
+}
+
+/// Sweep `FRAGMENT_DEADLINES_RX` and reclaim every `FragmentedPacket` slot in
+/// `fragments` whose reassembly has been idle past its deadline.
+///
+/// Only entries keyed by an IPv4 address are reclaimed here — IPv6 entries
+/// share the same deadlines table but live in `IPV6_FRAGMENTS_RX`, a
+/// separate pool, and are reclaimed by `fragment_pool_v6_sweep` instead, so
+/// that a v6 key is never passed to `FragmentSet::get_packet` (which would
+/// silently truncate its ident to 16 bits and could reclaim the wrong slot).
+///
+/// `FragmentSet`/`FragmentedPacket` are vendored from smoltcp and have no
+/// notion of time of their own, which is why this lives here rather than as
+/// a method on `FragmentSet` itself. `process_ipv4_fragment` calls this on
+/// every fragment it handles, but the firewall main loop can also poll it
+/// directly (e.g. on a timer, independent of traffic) by locking
+/// `FRAGMENTS_RX` itself. Returns the number of buffers reclaimed so the
+/// caller can log/drop as it sees fit.
+pub fn fragment_set_rx_sweep(fragments: &mut FragmentSet<'static>, now: Instant) -> usize {
+    let mut deadlines = FRAGMENT_DEADLINES_RX.lock();
+
+    let stale: Vec<FragmentKey> = deadlines
+        .iter()
+        .filter(|(k, state)| state.deadline <= now && matches!(k.src_addr, IpAddress::Ipv4(_)))
+        .map(|(k, _v)| *k)
+        .collect();
+
+    for stale_key in &stale {
+        debug_print!(
+            "Firewall fragment_set_rx_sweep: reassembly for id = {} timed out, reclaiming slot",
+            stale_key.ident
+        );
+        if let Some(frag) =
+            fragments.get_packet(stale_key.ident as u16, stale_key.src_addr, stale_key.dst_addr, now)
+        {
+            frag.reset();
+        }
+        deadlines.remove(stale_key);
+    }
+
+    stale.len()
+}
+
+/// The IPv6 counterpart to `fragment_set_rx_sweep`: reclaims every entry in
+/// `IPV6_FRAGMENTS_RX` whose reassembly has been idle past its deadline.
+/// Kept separate (rather than generalizing `fragment_set_rx_sweep`) because
+/// the two pools are different underlying types (`FragmentSet` vs a plain
+/// `HashMap`), not just different address families.
+pub fn fragment_pool_v6_sweep(
+    fragments: &mut HashMap<FragmentKey, FragmentedPacket<'static>>,
+    now: Instant,
+) -> usize {
+    let mut deadlines = FRAGMENT_DEADLINES_RX.lock();
+
+    let stale: Vec<FragmentKey> = deadlines
+        .iter()
+        .filter(|(k, state)| state.deadline <= now && matches!(k.src_addr, IpAddress::Ipv6(_)))
+        .map(|(k, _v)| *k)
+        .collect();
+
+    for stale_key in &stale {
+        debug_print!(
+            "Firewall fragment_pool_v6_sweep: reassembly for id = {} timed out, reclaiming slot",
+            stale_key.ident
+        );
+        fragments.remove(stale_key);
+        deadlines.remove(stale_key);
+    }
+
+    stale.len()
+}
+
+/// Process an IPv4 fragment
+/// Returns etiher a vector representing an assembled packet,
+/// nothing (in case no packets are available),
+/// or and error caused by fragment processing
+fn process_ipv4_fragment<'frame, 'r>(
+    ipv4_packet: Ipv4Packet<&'frame [u8]>,
+    timestamp: Instant,
+    fragments: &'r mut FragmentSet<'static>,
+) -> Result<Option<Vec<u8>>> {
+    debug_print!("Firewall process_ipv4_fragment: got a fragment with id = {}", ipv4_packet.ident());
+
+    let src_addr = IpAddress::from(ipv4_packet.src_addr());
+    let dst_addr = IpAddress::from(ipv4_packet.dst_addr());
+    let key = FragmentKey {
+        ident: ipv4_packet.ident() as u32,
+        src_addr,
+        dst_addr,
+    };
+
+    fragment_set_rx_sweep(fragments, timestamp);
+
+    {
+        let mut deadlines = FRAGMENT_DEADLINES_RX.lock();
+
+        if !deadlines.contains_key(&key) {
+            let deadline = timestamp + Duration::from_millis(REASSEMBLY_TIMEOUT_MS as u64);
+            deadlines.insert(
+                key,
+                FragmentState {
+                    deadline,
+                    buffered_bytes: 0,
+                    filled_ranges: vec![],
+                },
+            );
+        }
+    }
+
+    // non-final fragments must respect the 8-byte fragment-offset granularity,
+    // and the datagram they assemble into must never exceed the IPv4 maximum
+    let frag_start = ipv4_packet.frag_offset() as usize;
+    let frag_len = ipv4_payload_len(&ipv4_packet)?;
+    if ipv4_packet.more_frags() && frag_len % 8 != 0 {
+        debug_print!("Firewall process_ipv4_fragment: non-final fragment length not a multiple of 8");
+        FRAGMENT_DEADLINES_RX.lock().remove(&key);
+        return Err(Error::Malformed);
+    }
+    if frag_start + frag_len > 65535 {
+        debug_print!("Firewall process_ipv4_fragment: reassembled datagram would exceed 65535 bytes");
+        FRAGMENT_DEADLINES_RX.lock().remove(&key);
+        return Err(Error::Malformed);
+    }
+
+    // get an existing fragment or attempt to get a new one
+    let fragment = match fragments.get_packet(ipv4_packet.ident(), src_addr, dst_addr, timestamp) {
+        Some(frag) => frag,
+        None => return Err(Error::FragmentSetFull),
+    };
+
+    if fragment.is_empty() {
+        // this is a new packet
+        debug_print!("Firewall process_ipv4_fragment: fragment is empty");
+        fragment.start(ipv4_packet.ident(), src_addr, dst_addr);
+    }
+
+    let new_range = (frag_start, frag_start + frag_len);
+    {
+        let mut deadlines = FRAGMENT_DEADLINES_RX.lock();
+        if let Some(state) = deadlines.get_mut(&key) {
+            // teardrop/overlap evasion: a fragment that rewrites bytes another
+            // fragment already delivered can desync the firewall's view of
+            // the datagram from the endpoint's. What we do about it depends
+            // on `FRAGMENT_OVERLAP_POLICY`.
+            let new_bytes = new_bytes_in_range(&state.filled_ranges, new_range);
+            let overlaps_existing = new_bytes < frag_len;
+
+            if overlaps_existing {
+                match FRAGMENT_OVERLAP_POLICY {
+                    FragmentOverlapPolicy::Strict => {
+                        let payload = &ipv4_packet.payload()[..frag_len];
+                        if overlap_conflicts(fragment, ipv4_packet.header_len() as usize, frag_start, payload, &state.filled_ranges, new_range) {
+                            debug_print!(
+                                "Firewall process_ipv4_fragment: conflicting overlapping fragment for id = {}, Strict policy resets the reassembly",
+                                key.ident
+                            );
+                            fragment.reset();
+                            deadlines.remove(&key);
+                            return Err(Error::Malformed);
+                        }
+                        debug_print!(
+                            "Firewall process_ipv4_fragment: overlapping fragment for id = {} is a byte-identical retransmission, tolerating it",
+                            key.ident
+                        );
+                    }
+                    FragmentOverlapPolicy::FirstWins => {
+                        debug_print!(
+                            "Firewall process_ipv4_fragment: overlapping fragment for id = {}, FirstWins policy keeps the earlier data",
+                            key.ident
+                        );
+                        return Ok(None);
+                    }
+                    FragmentOverlapPolicy::LastWins => {
+                        debug_print!(
+                            "Firewall process_ipv4_fragment: overlapping fragment for id = {}, LastWins policy lets it overwrite",
+                            key.ident
+                        );
+                    }
+                }
+            }
+
+            // enforce a hard cap on the bytes we're willing to buffer for this
+            // key, regardless of what `set_total_len` eventually claims; only
+            // genuinely new bytes count; a tolerated duplicate retransmission
+            // (or an overwrite under `LastWins`) must not be charged again
+            state.buffered_bytes += new_bytes;
+            if state.buffered_bytes > constants::MAX_REASSEMBLED_FRAGMENT_SIZE {
+                debug_print!(
+                    "Firewall process_ipv4_fragment: reassembly for id = {} exceeds MAX_REASSEMBLED_FRAGMENT_SIZE, dropping",
+                    key.ident
+                );
+                fragment.reset();
+                deadlines.remove(&key);
+                return Err(Error::Malformed);
+            }
+
+            merge_range(&mut state.filled_ranges, new_range);
+        }
+    }
+
+    if !ipv4_packet.more_frags() {
+        // last fragment, remember data length
+        debug_print!("Firewall process_ipv4_fragment: this is the last fragment");
+        fragment
+            .set_total_len(ipv4_packet.frag_offset() as usize + ipv4_packet.total_len() as usize);
+    }
+
+    match fragment.add(
+        ipv4_packet.header_len() as usize,
+        ipv4_packet.frag_offset() as usize,
+        frag_len,
+        ipv4_packet.into_inner(),
+        timestamp,
+    ) {
+        Ok(_) => {
+            debug_print!("Firewall process_ipv4_fragment: adding fragment OK");
+        }
+        Err(_e) => {
+            debug_print!("Firewall process_ipv4_fragment: adding fragment error {:?}", _e);
+            fragment.reset();
+            FRAGMENT_DEADLINES_RX.lock().remove(&key);
+            return Err(Error::TooManyFragments);
+        }
+    }
+
+    if fragment.check_contig_range() {
+        // this is the last packet, attempt reassembly
+        let front = match fragment.front() {
+            Some(f) => {
+                debug_print!("Firewall process_ipv4_fragment: fragment reassembly Some");
+                f
+            }
+            None => {
+                debug_print!("Firewall process_ipv4_fragment: fragment reassebly None, return Ok(None)");
+                return Ok(None);
+            }
+        };
+        {
+            // because the different mutability of the underlying buffers, we have to do this exercise
+            let mut ipv4_packet = Ipv4Packet::new_checked(fragment.get_buffer_mut(0, front))?;
+            ipv4_packet.set_total_len(front as u16);
+            ipv4_packet.fill_checksum();
+        }
+        let ret = {
+            let mut ret = vec![0; front];
+            ret.clone_from_slice(fragment.get_buffer(0, front));
+            ret
+        };
+        fragment.reset();
+        FRAGMENT_DEADLINES_RX.lock().remove(&key);
+        return Ok(Some(ret));
+    }
+
+    // not the last fragment
+    let r = Ok(None);
+    debug_print!("Firewall process_ipv4_fragment: this wasn't the last fragment, returning {:?}", r);
+    return r;
+}
+
+/// Decode the question section of a DNS message: the QNAME (as a
+/// dot-separated name with compression pointers expanded), QTYPE and
+/// QCLASS of the first question. Only the first question is considered;
+/// DNS messages in practice carry exactly one.
+///
+/// Compression pointers (RFC 1035 section 4.1.4) are followed defensively:
+/// a pointer may only point backwards (preventing self-referential loops),
+/// and the number of pointer hops plus the number of labels are both
+/// capped (`DNS_MAX_POINTER_HOPS`/`DNS_MAX_LABELS`) so a crafted message
+/// can't force unbounded work. Any malformed input returns `Error::Malformed`
+/// so the caller fails closed rather than guessing.
+fn parse_dns_question(payload: &[u8]) -> Result<(Vec<u8>, u16, u16)> {
+    // fixed 12-byte DNS header: ID, flags, QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT
+    if payload.len() < 12 {
+        return Err(Error::Malformed);
+    }
+    let qdcount = u16::from_be_bytes([payload[4], payload[5]]);
+    if qdcount == 0 {
+        return Err(Error::Malformed);
+    }
+
+    let mut name = Vec::new();
+    let mut cursor = 12;
+    let mut hops = 0;
+    let mut labels = 0;
+    // once we follow a pointer, the question's QTYPE/QCLASS immediately
+    // follow the *original* label sequence, not the one at the pointer target
+    let mut end_of_question: Option<usize> = None;
+
+    loop {
+        if cursor >= payload.len() {
+            return Err(Error::Malformed);
+        }
+        let len_byte = payload[cursor];
+
+        if len_byte & 0xc0 == 0xc0 {
+            // compression pointer: 14-bit offset from the start of the message
+            if cursor + 1 >= payload.len() {
+                return Err(Error::Malformed);
+            }
+            let pointer = (((len_byte & 0x3f) as usize) << 8) | payload[cursor + 1] as usize;
+            if end_of_question.is_none() {
+                end_of_question = Some(cursor + 2);
+            }
+            hops += 1;
+            if hops > DNS_MAX_POINTER_HOPS || pointer >= cursor {
+                // forward/self pointers can't happen in a well-formed
+                // message and are the easiest way to build a loop
+                return Err(Error::Malformed);
+            }
+            cursor = pointer;
+            continue;
+        }
+
+        if len_byte == 0 {
+            // root label: end of name
+            cursor += 1;
+            break;
+        }
+
+        if len_byte & 0xc0 != 0 {
+            // the other two top-bit combinations are reserved/unused
+            return Err(Error::Malformed);
+        }
+
+        let label_len = len_byte as usize;
+        labels += 1;
+        if labels > DNS_MAX_LABELS || cursor + 1 + label_len > payload.len() {
+            return Err(Error::Malformed);
+        }
+
+        if !name.is_empty() {
+            name.push(b'.');
+        }
+        name.extend_from_slice(&payload[cursor + 1..cursor + 1 + label_len]);
+        cursor += 1 + label_len;
+    }
+
+    let question_end = end_of_question.unwrap_or(cursor);
+    if question_end + 4 > payload.len() {
+        return Err(Error::Malformed);
+    }
+    let qtype = u16::from_be_bytes([payload[question_end], payload[question_end + 1]]);
+    let qclass = u16::from_be_bytes([payload[question_end + 2], payload[question_end + 3]]);
+
+    Ok((name, qtype, qclass))
+}
+
+/// Process UDP data and eithe return an DP packet approved by the external firewall,
+/// or an error (including Error:Dropped)
+/// The processing is following:
+/// - parse UDP packet
+/// - create a new vector with the payload
+/// - call external firewall (if not NULL)
+/// - if approved, assembled a new UDP packet
 /// - otherwise return Error
 fn process_udp<'frame>(
     ip_repr: Ipv4Repr,
     ip_payload: &'frame [u8],
     external_firewall_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapper>>,
+    external_firewall_dns_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapperDns>>,
 ) -> Result<UdpPacket<Vec<u8>>> {
     let udp_packet = UdpPacket::new_checked(ip_payload)?;
     let checksum_caps = ChecksumCapabilities::default();
@@ -809,15 +2115,44 @@ fn process_udp<'frame>(
         max_data_len as u16,
     );
 
-    let payload_len = external_firewall_fn.lock().call(
-        src_addr_bytes,
-        udp_packet.src_port(),
-        dst_addr_bytes,
-        udp_packet.dst_port(),
-        data_len as u16,
-        data_ptr,
-        max_data_len as u16,
-    );
+    // port 53 gets application-layer visibility: decode the query name/type
+    // and hand it to the DNS-aware callback instead of the raw one, so
+    // policy can allow/deny by domain rather than only by address and port.
+    // A malformed DNS message on port 53 fails closed rather than falling
+    // back to the raw path, since that's exactly the kind of packet a
+    // tunnel/exfiltration attempt would send.
+    let payload_len = if udp_packet.src_port() == 53 || udp_packet.dst_port() == 53 {
+        let (qname, qtype, qclass) = parse_dns_question(udp_packet.payload())?;
+        debug_print!(
+            "Firewall process_udp: DNS question, qname = {:?}, qtype = {}, qclass = {}",
+            String::from_utf8_lossy(&qname),
+            qtype,
+            qclass,
+        );
+        external_firewall_dns_fn.lock().call(
+            src_addr_bytes,
+            udp_packet.src_port(),
+            dst_addr_bytes,
+            udp_packet.dst_port(),
+            qtype,
+            qclass,
+            qname.as_ptr(),
+            qname.len() as u16,
+            data_len as u16,
+            data_ptr,
+            max_data_len as u16,
+        )
+    } else {
+        external_firewall_fn.lock().call(
+            src_addr_bytes,
+            udp_packet.src_port(),
+            dst_addr_bytes,
+            udp_packet.dst_port(),
+            data_len as u16,
+            data_ptr,
+            max_data_len as u16,
+        )
+    };
 
     // update vector
     unsafe {
@@ -858,3 +2193,521 @@ fn process_udp<'frame>(
         return e;
     }
 }
+
+/// Process a TCP segment and either return a segment approved by the
+/// external firewall, or an error (including `Error::Dropped`).
+/// Unlike `process_udp`, segments are also checked against `TCP_CONN_TABLE`:
+/// only a bare SYN (no ACK) or a segment belonging to a flow whose SYN was
+/// already approved is handed to the external firewall at all; everything
+/// else is default-denied as an out-of-state segment. FIN/RST evict the
+/// flow from the table.
+fn process_tcp<'frame>(
+    ip_repr: Ipv4Repr,
+    ip_payload: &'frame [u8],
+    external_firewall_fn: Arc<camkesrust::Mutex<ExternalFirewallWrapperTcp>>,
+) -> Result<TcpPacket<Vec<u8>>> {
+    let tcp_packet = TcpPacket::new_checked(ip_payload)?;
+    let checksum_caps = ChecksumCapabilities::default();
+    let tcp_repr = TcpRepr::parse(
+        &tcp_packet,
+        &IpAddress::from(ip_repr.src_addr),
+        &IpAddress::from(ip_repr.dst_addr),
+        &checksum_caps,
+    )?;
+
+    let src_addr_bytes = ipv4_addr_to_u32(&ip_repr.src_addr);
+    let dst_addr_bytes = ipv4_addr_to_u32(&ip_repr.dst_addr);
+
+    let key = TcpConnKey {
+        src_addr: src_addr_bytes,
+        src_port: tcp_packet.src_port(),
+        dst_addr: dst_addr_bytes,
+        dst_port: tcp_packet.dst_port(),
+    };
+    let reply_key = TcpConnKey {
+        src_addr: dst_addr_bytes,
+        src_port: tcp_packet.dst_port(),
+        dst_addr: src_addr_bytes,
+        dst_port: tcp_packet.src_port(),
+    };
+
+    let now = timestamp();
+    let is_syn_only = tcp_packet.syn() && !tcp_packet.ack();
+    let is_established;
+
+    {
+        let mut table = TCP_CONN_TABLE.lock();
+        evict_stale_connections(&mut table, now);
+
+        // check against the table *before* evicting, or a FIN/RST for a flow
+        // this firewall itself approved would always look "unknown" by the
+        // time we get to the default-deny check below
+        is_established = table.contains_key(&key) || table.contains_key(&reply_key);
+
+        if tcp_packet.rst() || tcp_packet.fin() {
+            debug_print!("Firewall process_tcp: FIN/RST, evicting flow from connection table");
+            table.remove(&key);
+            table.remove(&reply_key);
+        }
+    }
+
+    if !is_syn_only && !is_established {
+        debug_print!("Firewall process_tcp: segment for unknown flow, default-deny");
+        return Err(Error::Dropped);
+    }
+
+    // prepare data
+    let mut tcp_data = Vec::with_capacity(constants::MAX_UDP_PAYLOAD_SIZE);
+    tcp_data.extend_from_slice(tcp_packet.payload());
+    let data_len = tcp_data.len();
+    let max_data_len = tcp_data.capacity();
+    let data_ptr = tcp_data.as_mut_ptr();
+
+    // pack the control bits the callback needs to tell a bare SYN from a
+    // data-bearing segment on an established connection, etc.
+    let mut flags: u8 = 0;
+    if tcp_packet.fin() {
+        flags |= TCP_FLAG_FIN;
+    }
+    if tcp_packet.syn() {
+        flags |= TCP_FLAG_SYN;
+    }
+    if tcp_packet.rst() {
+        flags |= TCP_FLAG_RST;
+    }
+    if tcp_packet.ack() {
+        flags |= TCP_FLAG_ACK;
+    }
+
+    debug_print!(
+        "Firewall process_tcp: calling external firewall.
+        src_addr = {},
+        tcp_packet.src_port = {},
+        dst_addr = {},
+        tcp_packet.dst_port = {},
+        flags = {:#x},
+        tcp payload len = {}
+        buffer size = {}",
+        ip_repr.src_addr,
+        tcp_packet.src_port(),
+        ip_repr.dst_addr,
+        tcp_packet.dst_port(),
+        flags,
+        data_len as u16,
+        max_data_len as u16,
+    );
+
+    let payload_len = external_firewall_fn.lock().call(
+        src_addr_bytes,
+        tcp_packet.src_port(),
+        dst_addr_bytes,
+        tcp_packet.dst_port(),
+        flags,
+        data_len as u16,
+        data_ptr,
+        max_data_len as u16,
+    );
+
+    unsafe {
+        mem::forget(tcp_data);
+        tcp_data = Vec::from_raw_parts(data_ptr, payload_len.max(0) as usize, max_data_len);
+    }
+
+    if payload_len < 0 {
+        let e = Err(Error::Dropped);
+        debug_print!("Firewall process_tcp: segment rejected, returning {:?}", e);
+        return e;
+    }
+
+    // only now that the segment was approved do we admit/refresh the flow;
+    // a FIN/RST was already evicted above and must stay evicted, not get
+    // re-admitted here
+    if tcp_packet.fin() || tcp_packet.rst() {
+        debug_print!("Firewall process_tcp: FIN/RST approved, forwarding and leaving flow evicted");
+    } else {
+        let deadline = now + Duration::from_millis(TCP_IDLE_TIMEOUT_MS as u64);
+        let mut table = TCP_CONN_TABLE.lock();
+        if is_syn_only {
+            debug_print!("Firewall process_tcp: SYN approved, admitting flow to connection table");
+            table.insert(key, deadline);
+        } else {
+            table.insert(key, deadline);
+            table.insert(reply_key, deadline);
+        }
+    }
+
+    let mut new_repr = tcp_repr.clone();
+    new_repr.payload = &tcp_data;
+    let mut tcp_packet_data = vec![0; new_repr.buffer_len()];
+    {
+        let mut tcp_packet_out = TcpPacket::new(tcp_packet_data.as_mut_slice());
+        new_repr.emit(
+            &mut tcp_packet_out,
+            &IpAddress::from(ip_repr.src_addr),
+            &IpAddress::from(ip_repr.dst_addr),
+            &checksum_caps,
+        );
+    }
+
+    let r = Ok(TcpPacket::new_checked(tcp_packet_data)?);
+    debug_print!("Firewall process_tcp: tcp segment created, returning OK");
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{TcpControl, TcpSeqNumber, Ipv6Address};
+
+    fn dns_question(name_labels: &[&[u8]], qtype: u16, qclass: u16) -> Vec<u8> {
+        let mut msg = vec![0u8; 12];
+        msg[5] = 1; // QDCOUNT = 1
+        for label in name_labels {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label);
+        }
+        msg.push(0); // root label
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&qclass.to_be_bytes());
+        msg
+    }
+
+    #[test]
+    fn parse_dns_question_accepts_well_formed_name() {
+        let msg = dns_question(&[b"www", b"example", b"com"], 1, 1);
+        let (name, qtype, qclass) = parse_dns_question(&msg).unwrap();
+        assert_eq!(name, b"www.example.com");
+        assert_eq!(qtype, 1);
+        assert_eq!(qclass, 1);
+    }
+
+    #[test]
+    fn parse_dns_question_rejects_pointer_loop() {
+        // a message whose only label is a compression pointer at offset 12
+        // pointing right back at offset 12: an infinite loop if followed
+        let mut msg = vec![0u8; 12];
+        msg[5] = 1; // QDCOUNT = 1
+        msg.extend_from_slice(&[0xc0, 12]);
+        msg.extend_from_slice(&1u16.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes());
+        assert!(parse_dns_question(&msg).is_err());
+    }
+
+    #[test]
+    fn parse_dns_question_rejects_forward_pointer() {
+        // a pointer must only ever point backwards; this one points past
+        // itself, which can't happen in a well-formed message
+        let mut msg = vec![0u8; 12];
+        msg[5] = 1; // QDCOUNT = 1
+        msg.extend_from_slice(&[0xc0, 20]);
+        msg.extend_from_slice(&1u16.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes());
+        assert!(parse_dns_question(&msg).is_err());
+    }
+
+    #[test]
+    fn range_overlap_detects_and_ignores_disjoint_ranges() {
+        assert_eq!(range_overlap((0, 10), (10, 20)), None);
+        assert_eq!(range_overlap((0, 10), (5, 15)), Some((5, 10)));
+        assert_eq!(range_overlap((5, 15), (0, 10)), Some((5, 10)));
+    }
+
+    #[test]
+    fn merge_range_coalesces_adjacent_and_overlapping_ranges() {
+        let mut ranges = vec![(0, 10)];
+        merge_range(&mut ranges, (10, 20));
+        assert_eq!(ranges, vec![(0, 20)]);
+
+        let mut ranges = vec![(0, 10), (20, 30)];
+        merge_range(&mut ranges, (5, 25));
+        assert_eq!(ranges, vec![(0, 30)]);
+    }
+
+    unsafe extern "C" fn approve_all(
+        _src_addr: u32,
+        _src_port: u16,
+        _dst_addr: u32,
+        _dst_port: u16,
+        _flags: u8,
+        payload_len: u16,
+        _payload: *const u8,
+        _max_payload_len: u16,
+    ) -> i32 {
+        payload_len as i32
+    }
+
+    fn tcp_segment(
+        src_addr: Ipv4Address,
+        src_port: u16,
+        dst_addr: Ipv4Address,
+        dst_port: u16,
+        control: TcpControl,
+        ack_number: Option<TcpSeqNumber>,
+    ) -> (Ipv4Repr, Vec<u8>) {
+        let tcp_repr = TcpRepr {
+            src_port,
+            dst_port,
+            control,
+            seq_number: TcpSeqNumber(0),
+            ack_number,
+            window_len: 1024,
+            window_scale: None,
+            max_seg_size: None,
+            sack_permitted: false,
+            sack_ranges: [None, None, None],
+            payload: &[],
+        };
+        let mut buf = vec![0; tcp_repr.buffer_len()];
+        tcp_repr.emit(
+            &mut TcpPacket::new(&mut buf[..]),
+            &IpAddress::Ipv4(src_addr),
+            &IpAddress::Ipv4(dst_addr),
+            &ChecksumCapabilities::default(),
+        );
+        let ip_repr = Ipv4Repr {
+            src_addr,
+            dst_addr,
+            protocol: IpProtocol::Tcp,
+            payload_len: buf.len(),
+            hop_limit: 64,
+        };
+        (ip_repr, buf)
+    }
+
+    #[test]
+    fn process_tcp_forwards_fin_and_evicts_established_flow_instead_of_default_denying() {
+        let client = Ipv4Address::new(10, 11, 12, 13);
+        let server = Ipv4Address::new(10, 11, 12, 14);
+        let client_port = 52345;
+        let server_port = 52346;
+        let firewall = Arc::new(camkesrust::Mutex::new(ExternalFirewallWrapperTcp::new(approve_all)).unwrap());
+
+        let key = TcpConnKey {
+            src_addr: ipv4_addr_to_u32(&client),
+            src_port: client_port,
+            dst_addr: ipv4_addr_to_u32(&server),
+            dst_port: server_port,
+        };
+        let reply_key = TcpConnKey {
+            src_addr: ipv4_addr_to_u32(&server),
+            src_port: server_port,
+            dst_addr: ipv4_addr_to_u32(&client),
+            dst_port: client_port,
+        };
+
+        // bare SYN: admitted to the table as a half-open flow
+        let (ip_repr, syn) = tcp_segment(client, client_port, server, server_port, TcpControl::Syn, None);
+        process_tcp(ip_repr, &syn, firewall.clone()).expect("SYN should be approved");
+        assert!(TCP_CONN_TABLE.lock().contains_key(&key));
+
+        // a data segment on the same flow completes the handshake's effect:
+        // both directions are now known
+        let (ip_repr, data) = tcp_segment(
+            client,
+            client_port,
+            server,
+            server_port,
+            TcpControl::None,
+            Some(TcpSeqNumber(1)),
+        );
+        process_tcp(ip_repr, &data, firewall.clone()).expect("established segment should be approved");
+        assert!(TCP_CONN_TABLE.lock().contains_key(&key));
+        assert!(TCP_CONN_TABLE.lock().contains_key(&reply_key));
+
+        // FIN for this now-established flow must be forwarded, not
+        // default-denied as an unknown flow, and must evict the entries
+        let (ip_repr, fin) = tcp_segment(
+            client,
+            client_port,
+            server,
+            server_port,
+            TcpControl::Fin,
+            Some(TcpSeqNumber(2)),
+        );
+        process_tcp(ip_repr, &fin, firewall.clone()).expect("FIN for an established flow should be forwarded");
+        assert!(!TCP_CONN_TABLE.lock().contains_key(&key));
+        assert!(!TCP_CONN_TABLE.lock().contains_key(&reply_key));
+
+        // and the flow must stay evicted: a further data segment on it is
+        // now out-of-state and default-denied
+        let (ip_repr, data_after_fin) = tcp_segment(
+            client,
+            client_port,
+            server,
+            server_port,
+            TcpControl::None,
+            Some(TcpSeqNumber(3)),
+        );
+        assert!(process_tcp(ip_repr, &data_after_fin, firewall).is_err());
+    }
+
+    fn ipv4_fragment_set() -> FragmentSet<'static> {
+        let mut fragments = FragmentSet::new(vec![]);
+        for _idx in 0..constants::SUPPORTED_FRAGMENTS {
+            fragments.add(FragmentedPacket::new(vec![0; constants::MAX_REASSEMBLED_FRAGMENT_SIZE]));
+        }
+        fragments
+    }
+
+    fn ipv4_fragment(
+        src_addr: Ipv4Address,
+        dst_addr: Ipv4Address,
+        ident: u16,
+        frag_offset: u16,
+        more_frags: bool,
+        payload: &[u8],
+    ) -> Ipv4Packet<Vec<u8>> {
+        let ip_repr = Ipv4Repr {
+            src_addr,
+            dst_addr,
+            protocol: IpProtocol::Udp,
+            payload_len: payload.len(),
+            hop_limit: 64,
+        };
+        let mut buf = vec![0; ip_repr.buffer_len() + payload.len()];
+        {
+            let mut packet = Ipv4Packet::new(&mut buf[..]);
+            ip_repr.emit(&mut packet, &ChecksumCapabilities::default());
+            packet.set_ident(ident);
+            packet.set_frag_offset(frag_offset);
+            packet.set_more_frags(more_frags);
+            packet.payload_mut().copy_from_slice(payload);
+            packet.fill_checksum();
+        }
+        Ipv4Packet::new_checked(buf).unwrap()
+    }
+
+    #[test]
+    fn process_ipv4_fragment_strict_policy_resets_on_conflict_and_tolerates_duplicates() {
+        let src = Ipv4Address::new(10, 20, 30, 1);
+        let dst = Ipv4Address::new(10, 20, 30, 2);
+        let ident = 0x1234;
+        let mut fragments = ipv4_fragment_set();
+
+        // first fragment of a two-fragment datagram
+        let first = ipv4_fragment(src, dst, ident, 0, true, &[0xAAu8; 8]);
+        let assembled = process_ipv4_fragment(first, timestamp(), &mut fragments).unwrap();
+        assert!(assembled.is_none(), "reassembly isn't complete yet");
+
+        // a byte-identical retransmission of the same fragment is a normal,
+        // non-malicious occurrence on a lossy link and must be tolerated,
+        // not treated as a conflicting overlap
+        let retransmit = ipv4_fragment(src, dst, ident, 0, true, &[0xAAu8; 8]);
+        let assembled = process_ipv4_fragment(retransmit, timestamp(), &mut fragments).unwrap();
+        assert!(assembled.is_none());
+
+        // a fragment covering the same range with *different* bytes is a
+        // genuine conflict: the Strict policy must reset the reassembly
+        let conflicting = ipv4_fragment(src, dst, ident, 0, true, &[0xBBu8; 8]);
+        assert!(process_ipv4_fragment(conflicting, timestamp(), &mut fragments).is_err());
+
+        // the reassembly was reset: the same id can start over from scratch
+        // and complete normally
+        let restart = ipv4_fragment(src, dst, ident, 0, true, &[0xCCu8; 8]);
+        let assembled = process_ipv4_fragment(restart, timestamp(), &mut fragments).unwrap();
+        assert!(assembled.is_none());
+        let last = ipv4_fragment(src, dst, ident, 8, false, &[0xDDu8; 8]);
+        let assembled = process_ipv4_fragment(last, timestamp(), &mut fragments).unwrap();
+        assert!(assembled.is_some(), "reassembly should complete once every fragment has arrived");
+    }
+
+    unsafe extern "C" fn deny_all(
+        _src_addr: u32,
+        _src_port: u16,
+        _dst_addr: u32,
+        _dst_port: u16,
+        _payload_len: u16,
+        _payload: *const u8,
+        _max_payload_len: u16,
+    ) -> i32 {
+        -1
+    }
+
+    /// One IPv6 fragment: an 8-byte Fragment extension header followed by
+    /// `fragment_payload`, wrapped in an Ethernet frame.
+    fn ipv6_fragment_frame(
+        src: Ipv6Address,
+        dst: Ipv6Address,
+        ident: u32,
+        frag_offset: u16,
+        more_frags: bool,
+        upper_protocol: IpProtocol,
+        fragment_payload: &[u8],
+    ) -> EthernetFrame<Vec<u8>> {
+        let mut frag_header = vec![0u8; 8];
+        frag_header[0] = u8::from(upper_protocol);
+        let offset_and_flags: u16 = (frag_offset << 3) | if more_frags { 1 } else { 0 };
+        frag_header[2..4].copy_from_slice(&offset_and_flags.to_be_bytes());
+        frag_header[4..8].copy_from_slice(&ident.to_be_bytes());
+
+        let mut ipv6_payload = frag_header;
+        ipv6_payload.extend_from_slice(fragment_payload);
+
+        let ip_repr = Ipv6Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: IpProtocol::Ipv6Frag,
+            payload_len: ipv6_payload.len(),
+            hop_limit: 64,
+        };
+        let mut buf = vec![0u8; constants::ETHERNET_FRAME_PAYLOAD + ip_repr.buffer_len() + ipv6_payload.len()];
+        {
+            let mut eth_frame = EthernetFrame::new(&mut buf[..]);
+            eth_frame.set_src_addr(EthernetAddress([1, 2, 3, 4, 5, 6]));
+            eth_frame.set_dst_addr(EthernetAddress([6, 5, 4, 3, 2, 1]));
+            eth_frame.set_ethertype(EthernetProtocol::Ipv6);
+        }
+        {
+            let mut ip_packet = Ipv6Packet::new(&mut buf[constants::ETHERNET_FRAME_PAYLOAD..]);
+            ip_repr.emit(&mut ip_packet);
+            ip_packet.payload_mut()[..ipv6_payload.len()].copy_from_slice(&ipv6_payload);
+        }
+        EthernetFrame::new_checked(buf).unwrap()
+    }
+
+    #[test]
+    fn process_ipv6_reassembles_fragmented_udp_and_still_runs_it_through_the_external_firewall() {
+        let src = Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        let dst = Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+        let ident = 0xA1A1_A1A1;
+
+        let udp_repr = UdpRepr {
+            src_port: 5353,
+            dst_port: 5353,
+            payload: &[0xABu8; 8],
+        };
+        let mut udp_bytes = vec![0u8; udp_repr.buffer_len()];
+        {
+            let mut udp_packet = UdpPacket::new(&mut udp_bytes[..]);
+            udp_repr.emit(
+                &mut udp_packet,
+                &IpAddress::Ipv6(src),
+                &IpAddress::Ipv6(dst),
+                &ChecksumCapabilities::default(),
+            );
+            udp_packet.fill_checksum(&IpAddress::Ipv6(src), &IpAddress::Ipv6(dst));
+        }
+        // split the (8-byte header + 8-byte payload) UDP datagram into two
+        // fragments at an 8-byte boundary
+        let (first_half, second_half) = udp_bytes.split_at(8);
+
+        let firewall = Arc::new(camkesrust::Mutex::new(ExternalFirewallWrapper::new(deny_all)).unwrap());
+
+        // reassembly isn't complete after only the first fragment: nothing
+        // to forward yet
+        let first_frame = ipv6_fragment_frame(src, dst, ident, 0, true, IpProtocol::Udp, first_half);
+        assert!(matches!(process_ipv6(first_frame, firewall.clone()), Err(Error::Fragmented)));
+
+        let last_frame = ipv6_fragment_frame(src, dst, ident, 1, false, IpProtocol::Udp, second_half);
+        // if the reassembled datagram were (as before this fix) emitted
+        // straight out without re-deriving the upper layer protocol, the
+        // external firewall callback above would never run and this would
+        // return Ok(_) instead of the Err(Error::Dropped) a denying
+        // firewall produces
+        let result = process_ipv6(last_frame, firewall);
+        assert!(
+            matches!(result, Err(Error::Dropped)),
+            "a fragmented UDPv6 datagram must still be run through the external firewall once reassembled"
+        );
+    }
+}